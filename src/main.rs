@@ -1,8 +1,14 @@
-use postgres::Error as PostgresError;
-use postgres::{Client, NoTls};
-use std::env;
-use std::io::{Read, Write};
-use std::net::{TcpListener, TcpStream};
+use bb8_postgres::PostgresConnectionManager;
+use std::future::Future;
+use std::pin::Pin;
+use std::thread::available_parallelism;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use bytes::BytesMut;
+use tokio_postgres::types::{to_sql_checked, IsNull, ToSql, Type};
+use tokio_postgres::{Client, Error as PostgresError, IsolationLevel, NoTls, Row, Transaction};
+use uuid::Uuid;
 
 /**
  * - `serde` is a serialization/deserialization library for Rust
@@ -10,60 +16,168 @@ use std::net::{TcpListener, TcpStream};
 #[macro_use]
 extern crate serde_derive;
 
+// Connection pool type alias
+/**
+ * - `bb8` is the async analog of `r2d2`; `bb8_postgres` plugs the `tokio_postgres`
+ *   client into it via a `PostgresConnectionManager`
+ * - A `Pool` is cheap to `clone()` (it is an `Arc` internally) so every spawned
+ *   task gets its own handle, and the `PooledConnection`s it hands out `Deref` to
+ *   `tokio_postgres::Client` and return to the pool automatically when dropped
+ */
+type Pool = bb8::Pool<PostgresConnectionManager<NoTls>>;
+
 // Model: User struct
+/**
+ * - `id` is a non-sequential `uuid` assigned by the database, so it is
+ *   `Option` (absent on the request body, present on every row we read back)
+ * - `metadata` is an arbitrary `jsonb` blob letting callers attach structured
+ *   attributes without a schema migration; it defaults to `{}` when omitted
+ */
 #[derive(Serialize, Deserialize)]
 struct User {
-    id: Option<i32>,
+    id: Option<Uuid>,
     name: String,
     email: String,
+    #[serde(default = "default_metadata")]
+    metadata: serde_json::Value,
+}
+
+// An empty JSON object, used when a request body omits `metadata`
+/**
+ * - Defaulting to `{}` rather than `null` keeps the value insertable into the
+ *   `NOT NULL` `metadata` column and readable back as a plain `Value`
+ */
+fn default_metadata() -> serde_json::Value {
+    serde_json::Value::Object(serde_json::Map::new())
+}
+
+// Body for the generic `POST /query` endpoint
+/**
+ * - `query` is a single read-only SQL statement and `params` are the values
+ *   bound positionally into it (`$1`, `$2`, …); `params` defaults to empty so a
+ *   parameter-less query can omit it
+ */
+#[derive(Deserialize)]
+struct QueryRequest {
+    query: String,
+    #[serde(default)]
+    params: Vec<serde_json::Value>,
 }
 
 // DATABASE URL
 const DB_URL: &str = env!("DATABASE_URL");
 
+// CORS headers shared by every response
+/**
+ * - The allowed origin is read from `CORS_ALLOW_ORIGIN` at compile time, the
+ *   same way `DATABASE_URL` is, so a browser SPA can be pointed at this API
+ *   without editing the source
+ * - `env!` expands to a string literal, so `concat!` can fold the header block
+ *   straight into each response constant below; each constant already starts
+ *   with its status line, so callers just append their body
+ */
+macro_rules! cors_headers {
+    () => {
+        concat!(
+            "Access-Control-Allow-Origin: ",
+            env!("CORS_ALLOW_ORIGIN"),
+            "\r\n",
+            "Access-Control-Allow-Methods: GET, POST, PUT, DELETE, OPTIONS\r\n",
+            "Access-Control-Allow-Headers: Content-Type\r\n",
+        )
+    };
+}
+
 // Response constants
-const OK_RESPONSE: &str = "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\n\r\n";
-const NOT_FOUND: &str = "HTTP/1.1 404 NOT FOUND\r\n\r\n";
-const INTERNAL_ERROR: &str = "HTTP/1.1 500 INTERNAL ERROR\r\n\r\n";
+const OK_RESPONSE: &str = concat!(
+    "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\n",
+    cors_headers!(),
+    "\r\n"
+);
+const BAD_REQUEST: &str = concat!(
+    "HTTP/1.1 400 BAD REQUEST\r\nContent-Type: application/json\r\n",
+    cors_headers!(),
+    "\r\n"
+);
+const CONFLICT: &str = concat!(
+    "HTTP/1.1 409 CONFLICT\r\nContent-Type: application/json\r\n",
+    cors_headers!(),
+    "\r\n"
+);
+const NOT_FOUND: &str = concat!("HTTP/1.1 404 NOT FOUND\r\n", cors_headers!(), "\r\n");
+const INTERNAL_ERROR: &str = concat!("HTTP/1.1 500 INTERNAL ERROR\r\n", cors_headers!(), "\r\n");
+const PAYLOAD_TOO_LARGE: &str =
+    concat!("HTTP/1.1 413 PAYLOAD TOO LARGE\r\n", cors_headers!(), "\r\n");
+const NO_CONTENT: &str = concat!("HTTP/1.1 204 NO CONTENT\r\n", cors_headers!(), "\r\n");
+
+// Largest request body we are willing to buffer before answering `413`
+const MAX_BODY_SIZE: usize = 1024 * 1024;
 
 // Main function
-fn main() {
+#[tokio::main]
+async fn main() {
+    // Build the shared connection pool
+    /*
+     * - `PostgresConnectionManager::new_from_stringlike()` parses the connection
+     *   string once and knows how to open fresh connections when the pool grows
+     * - The pool is sized to the number of available CPUs so we bound the number
+     *   of backend connections under load instead of opening one per request
+     * - `available_parallelism()` falls back to a single connection if the hint
+     *   is unavailable; the pool is built once here and cloned into each task
+     */
+    let manager = match PostgresConnectionManager::new_from_stringlike(DB_URL, NoTls) {
+        Ok(manager) => manager,
+        Err(_) => {
+            println!("Error parsing database url");
+            return;
+        }
+    };
+    let max_size = available_parallelism().map(|n| n.get() as u32).unwrap_or(1);
+    let pool = match Pool::builder().max_size(max_size).build(manager).await {
+        Ok(pool) => pool,
+        Err(_) => {
+            println!("Error building connection pool");
+            return;
+        }
+    };
+
     // Set up database
-    /**
+    /*
      * - Calls the set_database() function and checks if it returns an error
      * - `if let` is a pattern matching construct that only executes the code block if the pattern matches
      * - Err(_) means "if there's an error
      * - If there's an error, print "Error setting database" and return
      * - If there's no error, continue
      */
-    if let Err(_) = set_database() {
+    if let Err(_) = set_database(&pool).await {
         println!("Error setting database");
         return;
     }
 
     // Create a listener on port 3000
-    /**
+    /*
      * - `TcpListener::bind()` creates a server that can accept connections
      * - 0.0.0.0 means "listen on all available network interfaces"
      * - unwrap() gets the result value or crashes if there's an error
      */
-    let listener = TcpListener::bind(format!("0.0.0.0:3000")).unwrap();
+    let listener = TcpListener::bind("0.0.0.0:3000").await.unwrap();
     println!("Server listening on port 3000");
 
     // Listen for incoming connections
-    /**
-     * - `listener.incoming()` returns an iterator over incoming connections
-     * - `for` loops over each incoming connection
-     * - `match` is a pattern matching construct
-     * - Each connection is a Result type that might be either
-     *  - Ok(stream) if the connection is successful
-     *  - Err(e) if there's an error
-     * - If the connection is successful, call the `handle_client()` function to handle the request
+    /*
+     * - `listener.accept()` awaits the next incoming connection without blocking
+     *   the runtime, so other in-flight tasks keep making progress
+     * - Each accepted connection is handed to its own `tokio::spawn`ed task with
+     *   a cloned pool handle, letting many requests be served concurrently while
+     *   they await Postgres rather than being serialized on one accept loop
      */
-    for stream in listener.incoming() {
-        match stream {
-            Ok(stream) => {
-                handle_client(stream);
+    loop {
+        match listener.accept().await {
+            Ok((stream, _)) => {
+                let pool = pool.clone();
+                tokio::spawn(async move {
+                    handle_client(stream, &pool).await;
+                });
             }
             Err(e) => {
                 println!("Unable to connect: {}", e);
@@ -76,95 +190,175 @@ fn main() {
 /**
  * - Takes a mutable TCP stream as parameter (marked mut because we need to read from and write to it)
  */
-fn handle_client(mut stream: TcpStream) {
-    /**
-     * - Creates a buffer array of 1024 zeros to temporarily store incoming data
-     * - Creates an empty string to store the request
+async fn handle_client(mut stream: TcpStream, pool: &Pool) {
+    /*
+     * - Frames a full request off the stream before routing it: a single read
+     *   can return only part of the request, so `read_request` reads until the
+     *   headers terminate and then until `Content-Length` body bytes arrive
+     * - `Ok(None)` means the body blew past `MAX_BODY_SIZE`; answer `413` and
+     *   drop the connection rather than buffering an unbounded request
      */
-    let mut buffer = [0; 1024];
-    let mut request = String::new();
-
-    // Reads data from the stream into our buffer
-    match stream.read(&mut buffer) {
-        /**
-         * If read is successful:
-         * - `size` is how many bytes were read
-         * - `&buffer[..size]` takes a slice of the buffer up to the number of bytes read
-         * - `String::from_utf8_lossy()` converts the slice to a string
-         * - `.as_ref()` gets a reference to the string
-         * - `push_str()` appends the string to the request
-         */
-        Ok(size) => {
-            request.push_str(String::from_utf8_lossy(&buffer[..size]).as_ref());
-
-            /**
-             * - Uses pattern matching to check the start of the request string
-             * - Calls the appropriate function based on the request
-             * - The pattern r if r.starts_with(...) binds the request to r and checks if it starts with the given path
-             */
-            let (status_line, content) = match &*request {
-                r if r.starts_with("POST /users") => handle_post_request(r),
-                r if r.starts_with("GET /users/") => handle_get_request(r),
-                r if r.starts_with("GET /users") => handle_get_all_request(r),
-                r if r.starts_with("PUT /users/") => handle_put_request(r),
-                r if r.starts_with("DELETE /users/") => handle_delete_request(r),
-                _ => (NOT_FOUND.to_string(), "404 not found".to_string()),
-            };
+    let request = match read_request(&mut stream).await {
+        Ok(Some(request)) => request,
+        Ok(None) => {
+            let _ = stream
+                .write_all(
+                    format!("{}{}", PAYLOAD_TOO_LARGE, "413 payload too large").as_bytes(),
+                )
+                .await;
+            return;
+        }
+        Err(e) => {
+            eprintln!("Unable to read stream: {}", e);
+            return;
+        }
+    };
+
+    /*
+     * - Uses pattern matching to check the start of the request string
+     * - Calls the appropriate function based on the request
+     * - The pattern r if r.starts_with(...) binds the request to r and checks if it starts with the given path
+     */
+    let (status_line, content) = match &*request {
+        // CORS preflight: browsers send an OPTIONS before the real request
+        r if r.starts_with("OPTIONS ") => (NO_CONTENT.to_string(), String::new()),
+        r if r.starts_with("POST /query") => handle_query_request(r, pool).await,
+        r if r.starts_with("POST /users") => handle_post_request(r, pool).await,
+        r if r.starts_with("GET /users/") => handle_get_request(r, pool).await,
+        r if r.starts_with("GET /users") => handle_get_all_request(r, pool).await,
+        r if r.starts_with("PUT /users/") => handle_put_request(r, pool).await,
+        r if r.starts_with("DELETE /users/") => handle_delete_request(r, pool).await,
+        _ => (NOT_FOUND.to_string(), "404 not found".to_string()),
+    };
 
-            stream
-                .write_all(format!("{}{}", status_line, content).as_bytes())
-                .unwrap();
+    stream
+        .write_all(format!("{}{}", status_line, content).as_bytes())
+        .await
+        .unwrap();
+}
+
+// Read and frame a single HTTP request off the stream
+/**
+ * - A single `read()` may return less than a whole request, and under TCP
+ *   segmentation the headers and body can arrive in separate packets, so we
+ *   accumulate bytes until we have seen the blank line (`\r\n\r\n`) that ends
+ *   the headers
+ * - The `Content-Length` header then tells us exactly how many body bytes to
+ *   expect, and we keep reading until all of them have arrived
+ * - A body larger than `MAX_BODY_SIZE` returns `Ok(None)` so the caller can
+ *   answer `413` instead of buffering an unbounded request; a connection that
+ *   closes early yields whatever was received, which is then routed as is
+ */
+async fn read_request(stream: &mut TcpStream) -> Result<Option<String>, std::io::Error> {
+    let mut buffer = [0u8; 1024];
+    let mut data: Vec<u8> = Vec::new();
+
+    // read until the header terminator is seen
+    let header_end = loop {
+        if let Some(pos) = find_subsequence(&data, b"\r\n\r\n") {
+            break pos + 4;
+        }
+        let size = stream.read(&mut buffer).await?;
+        if size == 0 {
+            return Ok(Some(String::from_utf8_lossy(&data).into_owned()));
+        }
+        data.extend_from_slice(&buffer[..size]);
+    };
+
+    // pull the Content-Length out of the header block (absent means no body)
+    let content_length = String::from_utf8_lossy(&data[..header_end])
+        .lines()
+        .find_map(|line| {
+            let (name, value) = line.split_once(':')?;
+            if name.trim().eq_ignore_ascii_case("content-length") {
+                value.trim().parse::<usize>().ok()
+            } else {
+                None
+            }
+        })
+        .unwrap_or(0);
+
+    if content_length > MAX_BODY_SIZE {
+        return Ok(None);
+    }
+
+    // read until exactly `content_length` body bytes have been received
+    let total = header_end + content_length;
+    while data.len() < total {
+        let size = stream.read(&mut buffer).await?;
+        if size == 0 {
+            break;
         }
-        Err(e) => eprintln!("Unable to read stream: {}", e),
+        data.extend_from_slice(&buffer[..size]);
     }
+
+    Ok(Some(String::from_utf8_lossy(&data).into_owned()))
+}
+
+// Find the first index of `needle` within `haystack`
+fn find_subsequence(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack
+        .windows(needle.len())
+        .position(|window| window == needle)
 }
 
 // Handle post request
 /**
  * - Takes a string as parameter and returns a tuple of two strings (status_line, content)
  */
-fn handle_post_request(request: &str) -> (String, String) {
-    /**
+async fn handle_post_request(request: &str, pool: &Pool) -> (String, String) {
+    /*
      * This match block does the following:
      * - Calls the get_user_request_body() function to get the user data from the request body
-     * - Calls the Client::connect() function to connect to the database
+     * - Awaits `pool.get()` to check out a pooled connection for the request
      * - Both operations return Result types, which is why we use match
      */
-    match (
-        get_user_request_body(&request),
-        Client::connect(DB_URL, NoTls),
-    ) {
-        /**
+    match (get_user_request_body(request), pool.get().await) {
+        /*
          * - Ok(user) means we successfully parsed the User from request
-         * - Ok(mut client) means we successfully connected to the database
+         * - Ok(mut client) means we successfully checked out a connection
          */
         (Ok(user), Ok(mut client)) => {
-            client
-                .execute(
-                    "INSERT INTO users (name, email) VALUES ($1, $2)",
-                    &[&user.name, &user.email],
-                )
-                .unwrap();
-
-            (OK_RESPONSE.to_string(), "User created".to_string())
+            let result = with_retry(&mut client, move |transaction| {
+                // owned clones are moved into each attempt so the closure can be replayed
+                let (name, email, metadata) =
+                    (user.name.clone(), user.email.clone(), user.metadata.clone());
+                Box::pin(async move {
+                    transaction
+                        .execute(
+                            "INSERT INTO users (name, email, metadata) VALUES ($1, $2, $3)",
+                            &[&name, &email, &metadata],
+                        )
+                        .await
+                })
+            })
+            .await;
+            match result {
+                Ok(_) => (OK_RESPONSE.to_string(), "User created".to_string()),
+                Err(e) => db_error_response(&e),
+            }
         }
         _ => (INTERNAL_ERROR.to_string(), "Internal error".to_string()),
     }
 }
 
 //handle get request
-fn handle_get_request(request: &str) -> (String, String) {
-    match (
-        get_id(&request).parse::<i32>(),
-        Client::connect(DB_URL, NoTls),
-    ) {
-        (Ok(id), Ok(mut client)) => {
-            match client.query_one("SELECT * FROM users WHERE id = $1", &[&id]) {
+async fn handle_get_request(request: &str, pool: &Pool) -> (String, String) {
+    match (get_id(request).parse::<Uuid>(), pool.get().await) {
+        (Ok(id), Ok(client)) => {
+            match client
+                .query_one(
+                    "SELECT id, name, email, metadata FROM users WHERE id = $1",
+                    &[&id],
+                )
+                .await
+            {
                 Ok(row) => {
                     let user = User {
                         id: row.get(0),
                         name: row.get(1),
                         email: row.get(2),
+                        metadata: row.get(3),
                     };
 
                     (
@@ -181,19 +375,25 @@ fn handle_get_request(request: &str) -> (String, String) {
 }
 
 //handle get all request
-fn handle_get_all_request(_request: &str) -> (String, String) {
-    match Client::connect(DB_URL, NoTls) {
-        Ok(mut client) => {
+async fn handle_get_all_request(_request: &str, pool: &Pool) -> (String, String) {
+    match pool.get().await {
+        Ok(client) => {
             let mut users = Vec::new();
 
-            for row in client
-                .query("SELECT id, name, email FROM users", &[])
-                .unwrap()
+            let rows = match client
+                .query("SELECT id, name, email, metadata FROM users", &[])
+                .await
             {
+                Ok(rows) => rows,
+                Err(e) => return db_error_response(&e),
+            };
+
+            for row in rows {
                 users.push(User {
                     id: row.get(0),
                     name: row.get(1),
                     email: row.get(2),
+                    metadata: row.get(3),
                 });
             }
 
@@ -207,63 +407,319 @@ fn handle_get_all_request(_request: &str) -> (String, String) {
 }
 
 // Handle put request
-fn handle_put_request(request: &str) -> (String, String) {
+async fn handle_put_request(request: &str, pool: &Pool) -> (String, String) {
     match (
-        get_id(&request).parse::<i32>(),
-        get_user_request_body(&request),
-        Client::connect(DB_URL, NoTls),
+        get_id(request).parse::<Uuid>(),
+        get_user_request_body(request),
+        pool.get().await,
     ) {
         (Ok(id), Ok(user), Ok(mut client)) => {
-            client
-                .execute(
-                    "UPDATE users SET name = $1, email = $2 WHERE id = $3",
-                    &[&user.name, &user.email, &id],
-                )
-                .unwrap();
-
-            (OK_RESPONSE.to_string(), "User updated".to_string())
+            let result = with_retry(&mut client, move |transaction| {
+                // owned clones are moved into each attempt so the closure can be replayed
+                let (name, email, metadata) =
+                    (user.name.clone(), user.email.clone(), user.metadata.clone());
+                Box::pin(async move {
+                    transaction
+                        .execute(
+                            "UPDATE users SET name = $1, email = $2, metadata = $3 WHERE id = $4",
+                            &[&name, &email, &metadata, &id],
+                        )
+                        .await
+                })
+            })
+            .await;
+            match result {
+                Ok(_) => (OK_RESPONSE.to_string(), "User updated".to_string()),
+                Err(e) => db_error_response(&e),
+            }
         }
         _ => (INTERNAL_ERROR.to_string(), "Internal error".to_string()),
     }
 }
 
 // Handle delete request
-fn handle_delete_request(request: &str) -> (String, String) {
-    match (
-        get_id(&request).parse::<i32>(),
-        Client::connect(DB_URL, NoTls),
-    ) {
+async fn handle_delete_request(request: &str, pool: &Pool) -> (String, String) {
+    match (get_id(request).parse::<Uuid>(), pool.get().await) {
         (Ok(id), Ok(mut client)) => {
-            let rows_affected = client
-                .execute("DELETE FROM users WHERE id = $1", &[&id])
-                .unwrap();
-
-            //if rows affected is 0, user not found
-            if rows_affected == 0 {
-                return (NOT_FOUND.to_string(), "User not found".to_string());
+            let result = with_retry(&mut client, move |transaction| {
+                Box::pin(async move {
+                    transaction
+                        .execute("DELETE FROM users WHERE id = $1", &[&id])
+                        .await
+                })
+            })
+            .await;
+            match result {
+                // if rows affected is 0, user not found
+                Ok(0) => (NOT_FOUND.to_string(), "User not found".to_string()),
+                Ok(_) => (OK_RESPONSE.to_string(), "User deleted".to_string()),
+                Err(e) => db_error_response(&e),
             }
-
-            (OK_RESPONSE.to_string(), "User deleted".to_string())
         }
         _ => (INTERNAL_ERROR.to_string(), "Internal error".to_string()),
     }
 }
 
+// Handle a generic parameterized read-only query
+/**
+ * - Parses `{ "query": "...", "params": [...] }`, binds the params positionally
+ *   into `client.query`, and returns the resulting rows as a JSON array
+ * - Only `SELECT` statements are accepted: the trimmed, upper-cased statement
+ *   must start with `SELECT`, otherwise we answer `400`, so INSERT, UPDATE,
+ *   DELETE and DDL statements are rejected; only the leading keyword is
+ *   checked, so a `SELECT` that writes (`SELECT … INTO`, or one calling a
+ *   data-modifying function) is not rejected
+ * - Rows are serialized generically (see `row_to_json`), so callers get ad-hoc
+ *   querying over any table without a bespoke handler per shape
+ */
+async fn handle_query_request(request: &str, pool: &Pool) -> (String, String) {
+    let body: QueryRequest =
+        match serde_json::from_str(request.split("\r\n\r\n").last().unwrap_or_default()) {
+            Ok(body) => body,
+            Err(_) => return (BAD_REQUEST.to_string(), "Invalid request body".to_string()),
+        };
+
+    if !body.query.trim().to_uppercase().starts_with("SELECT") {
+        return (
+            BAD_REQUEST.to_string(),
+            "Only SELECT statements are allowed".to_string(),
+        );
+    }
+
+    let client = match pool.get().await {
+        Ok(client) => client,
+        Err(_) => return (INTERNAL_ERROR.to_string(), "Internal error".to_string()),
+    };
+
+    // convert each JSON param into an owned, bindable SQL value, then borrow them
+    let params: Vec<Box<dyn ToSql + Sync + Send>> = body.params.iter().map(json_to_sql).collect();
+    let param_refs: Vec<&(dyn ToSql + Sync)> = params
+        .iter()
+        .map(|p| p.as_ref() as &(dyn ToSql + Sync))
+        .collect();
+
+    match client.query(&body.query, &param_refs).await {
+        Ok(rows) => {
+            let result: Vec<serde_json::Value> = rows.iter().map(row_to_json).collect();
+            (
+                OK_RESPONSE.to_string(),
+                serde_json::to_string(&result).unwrap(),
+            )
+        }
+        Err(e) => db_error_response(&e),
+    }
+}
+
+// Convert a JSON param into an owned value that can be bound into a statement
+/**
+ * - Each variant maps to the Postgres type the driver infers for that literal;
+ *   JSON has no integer/float split, so whole numbers bind as `i64` and the
+ *   rest as `f64`
+ * - `null` binds as an untyped NULL (see `Null`) so Postgres infers the column
+ *   type from context, and objects/arrays bind as `jsonb`
+ */
+fn json_to_sql(value: &serde_json::Value) -> Box<dyn ToSql + Sync + Send> {
+    match value {
+        serde_json::Value::String(s) => Box::new(s.clone()),
+        serde_json::Value::Bool(b) => Box::new(*b),
+        serde_json::Value::Number(n) if n.is_i64() => Box::new(n.as_i64().unwrap()),
+        serde_json::Value::Number(n) => Box::new(n.as_f64().unwrap()),
+        serde_json::Value::Null => Box::new(Null),
+        other => Box::new(other.clone()),
+    }
+}
+
+// An untyped SQL NULL
+/**
+ * - `Option::<T>::None` binds a NULL of `T`'s Postgres type, which Postgres
+ *   rejects when that type doesn't match the column (e.g. a `TEXT` NULL against
+ *   a `uuid` predicate); accepting any type lets the server infer it from the
+ *   surrounding statement instead
+ */
+#[derive(Debug)]
+struct Null;
+
+impl ToSql for Null {
+    fn to_sql(
+        &self,
+        _ty: &Type,
+        _out: &mut BytesMut,
+    ) -> Result<IsNull, Box<dyn std::error::Error + Sync + Send>> {
+        Ok(IsNull::Yes)
+    }
+
+    fn accepts(_ty: &Type) -> bool {
+        true
+    }
+
+    to_sql_checked!();
+}
+
+// Serialize a single row into a JSON object keyed by column name
+/**
+ * - Walks `row.columns()` and dispatches on each `Column::type_()` to pull the
+ *   value out with the right Rust type before re-encoding it as JSON
+ * - Every column is read as an `Option` so a SQL `NULL` becomes a JSON `null`
+ * - Types outside the explicit set are read with `try_get` rather than `get`,
+ *   so a column with no `FromSql<String>` impl (timestamps, numerics, bytea, …)
+ *   becomes `null` instead of panicking the handler task
+ */
+fn row_to_json(row: &Row) -> serde_json::Value {
+    let mut map = serde_json::Map::new();
+    for (i, column) in row.columns().iter().enumerate() {
+        let value = match *column.type_() {
+            Type::BOOL => row
+                .get::<_, Option<bool>>(i)
+                .map_or(serde_json::Value::Null, serde_json::Value::from),
+            Type::INT4 => row
+                .get::<_, Option<i32>>(i)
+                .map_or(serde_json::Value::Null, serde_json::Value::from),
+            Type::INT8 => row
+                .get::<_, Option<i64>>(i)
+                .map_or(serde_json::Value::Null, serde_json::Value::from),
+            Type::FLOAT8 => row
+                .get::<_, Option<f64>>(i)
+                .map_or(serde_json::Value::Null, serde_json::Value::from),
+            Type::JSON | Type::JSONB => row
+                .get::<_, Option<serde_json::Value>>(i)
+                .unwrap_or(serde_json::Value::Null),
+            Type::UUID => row
+                .get::<_, Option<Uuid>>(i)
+                .map_or(serde_json::Value::Null, |id| {
+                    serde_json::Value::from(id.to_string())
+                }),
+            // unknown type: try to render it as text, yielding null if it has no
+            // `FromSql<String>` impl rather than panicking
+            _ => row
+                .try_get::<_, Option<String>>(i)
+                .ok()
+                .flatten()
+                .map_or(serde_json::Value::Null, serde_json::Value::from),
+        };
+        map.insert(column.name().to_string(), value);
+    }
+    serde_json::Value::Object(map)
+}
+
 // Set up database
-fn set_database() -> Result<(), PostgresError> {
-    let mut client = Client::connect(DB_URL, NoTls)?;
-    client.batch_execute(
-        "
+/**
+ * - Returns a boxed error so both failure modes — checking a connection out of
+ *   the pool (`bb8::RunError`) and running the DDL (`PostgresError`) — can be
+ *   propagated with `?` instead of panicking the process, letting `main`
+ *   report "Error setting database" and exit cleanly
+ */
+async fn set_database(pool: &Pool) -> Result<(), Box<dyn std::error::Error>> {
+    let client = pool.get().await?;
+    client
+        .batch_execute(
+            "
         CREATE TABLE IF NOT EXISTS users (
-            id SERIAL PRIMARY KEY,
+            id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
             name VARCHAR NOT NULL,
-            email VARCHAR NOT NULL
+            email VARCHAR NOT NULL,
+            metadata JSONB NOT NULL DEFAULT '{}'::jsonb
         )
     ",
-    )?;
+        )
+        .await?;
     Ok(())
 }
 
+// Maximum number of times a write transaction is retried on a transient failure
+const MAX_RETRIES: u32 = 5;
+
+// Run a closure inside a transaction, retrying on transient serialization failures
+/**
+ * - Opens a transaction, runs `f` against it, and commits on success
+ * - Under `SERIALIZABLE` / `REPEATABLE READ` isolation the server can abort a
+ *   transaction with a serialization failure (`40001`) or a deadlock
+ *   (`40P01`); these are not bugs, they just mean "try again"
+ * - On such a failure, whether raised by `f` or by the commit, the transaction
+ *   is rolled back and the closure is replayed, backing off exponentially (10ms, 20ms, 40ms, …) up to
+ *   `MAX_RETRIES` times before the last error is returned to the caller
+ * - `f` is `FnMut` because it is called once per attempt
+ */
+async fn with_retry<T, F>(
+    client: &mut Client,
+    mut f: F,
+) -> Result<T, PostgresError>
+where
+    F: for<'a, 'b> FnMut(
+        &'a mut Transaction<'b>,
+    ) -> Pin<Box<dyn Future<Output = Result<T, PostgresError>> + Send + 'a>>,
+{
+    let mut attempt = 0;
+    loop {
+        // SERIALIZABLE is what makes the `40001` retry path reachable; under the
+        // default READ COMMITTED the server never raises it (`40P01` deadlocks
+        // can happen at any isolation level)
+        let mut transaction = client
+            .build_transaction()
+            .isolation_level(IsolationLevel::Serializable)
+            .start()
+            .await?;
+        match f(&mut transaction).await {
+            Ok(value) => match transaction.commit().await {
+                Ok(()) => return Ok(value),
+                // serializable conflicts are often only detected at commit time
+                Err(e) if is_retryable(&e) && attempt < MAX_RETRIES => {
+                    tokio::time::sleep(Duration::from_millis(10 * 2u64.pow(attempt))).await;
+                    attempt += 1;
+                }
+                Err(e) => return Err(e),
+            },
+            Err(e) => {
+                // rolling the transaction back before we try again
+                let _ = transaction.rollback().await;
+                if is_retryable(&e) && attempt < MAX_RETRIES {
+                    tokio::time::sleep(Duration::from_millis(10 * 2u64.pow(attempt))).await;
+                    attempt += 1;
+                    continue;
+                }
+                return Err(e);
+            }
+        }
+    }
+}
+
+// Whether a Postgres error is a transient failure worth retrying
+/**
+ * - `40001` is `serialization_failure` and `40P01` is `deadlock_detected`; both
+ *   are resolved by replaying the transaction
+ */
+fn is_retryable(err: &PostgresError) -> bool {
+    matches!(
+        err.as_db_error().map(|e| e.code().code()),
+        Some("40001") | Some("40P01")
+    )
+}
+
+// Translate a Postgres error into an HTTP status line and body
+/**
+ * - A `tokio_postgres::Error` that came back from the server carries a `DbError` with
+ *   the SQLSTATE `code()` and a human-readable `message()`
+ * - Unique violations (`23505`) mean the client tried to create a duplicate, so
+ *   we answer `409 Conflict`
+ * - The rest of the integrity-constraint class (`23xxx` — not-null, check,
+ *   foreign-key) is caller error, so we answer `400 Bad Request`
+ * - Anything else (or an error with no `DbError`, e.g. a transport failure) is a
+ *   genuine `500`, and we echo the DB message so the cause isn't swallowed
+ */
+fn db_error_response(err: &PostgresError) -> (String, String) {
+    match err.as_db_error() {
+        Some(db_error) => {
+            let message = format!("{}: {}", db_error.severity(), db_error.message());
+            let status_line = match db_error.code().code() {
+                "23505" => CONFLICT,
+                code if code.starts_with("23") => BAD_REQUEST,
+                _ => INTERNAL_ERROR,
+            };
+            (status_line.to_string(), message)
+        }
+        None => (INTERNAL_ERROR.to_string(), err.to_string()),
+    }
+}
+
 // Get id from request
 /**
  * - Splits the request string by "/" and gets the third element (For "GET /users/123 HTTP/1.1" becomes: ["GET ", "users", "123 HTTP/1.1"]) and gets "123 HTTP/1.1"